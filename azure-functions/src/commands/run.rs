@@ -4,31 +4,173 @@ use crate::{
     logger,
     registry::Registry,
     rpc::{
-        status_result::Status, streaming_message::Content, FunctionLoadRequest,
-        FunctionLoadResponse, FunctionRpcClient, InvocationRequest, InvocationResponse,
-        StartStream, StatusResult, StreamingMessage, WorkerInitResponse, WorkerStatusRequest,
+        status_result::Status, streaming_message::Content, FunctionEnvironmentReloadRequest,
+        FunctionEnvironmentReloadResponse, FunctionLoadRequest, FunctionLoadResponse,
+        FunctionRpcClient, InvocationCancel, InvocationRequest, InvocationResponse, StartStream,
+        StatusResult, StreamingMessage, WorkerInitResponse, WorkerStatusRequest,
         WorkerStatusResponse,
     },
 };
 use clap::{App, Arg, ArgMatches, SubCommand};
-use futures::{future::lazy, sink::Sink, sync::mpsc::unbounded, Future, Stream};
+use futures::{sink::Sink, sync::mpsc::unbounded, Future, Stream};
 use grpcio::{ChannelBuilder, EnvBuilder, WriteFlags};
-use log::error;
+use lazy_static::lazy_static;
+use log::{debug, error};
 use std::cell::RefCell;
+use std::collections::HashMap;
 use std::panic::{catch_unwind, set_hook, AssertUnwindSafe, PanicInfo};
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::{Duration, Instant};
+use threadpool::ThreadPool;
+
+/// The default number of seconds to wait for in-flight invocations to drain
+/// during a graceful shutdown before giving up, when
+/// `--shutdownDrainTimeoutSeconds` is not specified.
+const DEFAULT_SHUTDOWN_DRAIN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The maximum time to wait for in-flight invocations to finish before a
+/// `FunctionEnvironmentReloadRequest` mutates the process environment.
+///
+/// Invocations run concurrently on the `InvocationPool`'s worker threads and
+/// may read environment variables (e.g. connection strings) while they
+/// execute, so the reload must not rewrite them out from under a running
+/// invocation; the host event loop thread therefore waits, up to this
+/// timeout, until it observes no in-flight invocations before running the
+/// reload. If invocations are still in flight when the timeout elapses, an
+/// error is logged and the reload proceeds while they run.
+const ENVIRONMENT_RELOAD_DRAIN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The default maximum number of invocations allowed to wait for a free
+/// worker before new ones are rejected, when `--maxQueuedInvocations` is not
+/// specified. Expressed as a multiple of the worker pool's concurrency so it
+/// scales with `--maxConcurrentInvocations`.
+const DEFAULT_MAX_QUEUED_INVOCATIONS_MULTIPLIER: usize = 4;
+
+/// Set once a shutdown signal has been received (either a SIGINT or the host
+/// closing the event stream); new invocations are rejected and the worker
+/// drains in-flight ones before exiting.
+static SHUTTING_DOWN: AtomicBool = AtomicBool::new(false);
+
+/// The number of `StreamingMessage`s (log messages and responses) that have
+/// been queued for the host but not yet handed off to the gRPC sink. Used to
+/// know when it's safe to close the stream during a graceful shutdown.
+static PENDING_MESSAGES: AtomicUsize = AtomicUsize::new(0);
+
+/// Queues a message to be sent to the host, tracking it as pending until the
+/// background forwarder thread picks it up.
+pub(crate) fn enqueue(
+    sender: &futures::sync::mpsc::UnboundedSender<StreamingMessage>,
+    msg: StreamingMessage,
+) -> Result<(), futures::sync::mpsc::SendError<StreamingMessage>> {
+    PENDING_MESSAGES.fetch_add(1, Ordering::SeqCst);
+    sender.unbounded_send(msg)
+}
 
 const UNKNOWN: &str = "<unknown>";
 
 thread_local!(static FUNCTION_NAME: RefCell<&'static str> = RefCell::new(UNKNOWN));
+thread_local!(static CANCELLATION_TOKEN: RefCell<Option<Arc<Invocation>>> = RefCell::new(None));
 
 type Sender = futures::sync::mpsc::UnboundedSender<StreamingMessage>;
 
+/// Tracks the cancellation and response state of an in-flight invocation.
+struct Invocation {
+    cancelled: AtomicBool,
+    responded: AtomicBool,
+}
+
+impl Invocation {
+    fn new() -> Invocation {
+        Invocation {
+            cancelled: AtomicBool::new(false),
+            responded: AtomicBool::new(false),
+        }
+    }
+}
+
+lazy_static! {
+    /// The set of invocations currently in-flight, keyed by invocation id.
+    static ref INVOCATIONS: Mutex<HashMap<String, Arc<Invocation>>> = Mutex::new(HashMap::new());
+}
+
+/// Returns true if the current invocation has been cancelled by the host.
+///
+/// Long-running or loop/timer-driven functions should poll this at natural
+/// await/loop points and bail out early when it returns true.
+pub fn is_cancelled() -> bool {
+    CANCELLATION_TOKEN.with(|token| {
+        token
+            .borrow()
+            .as_ref()
+            .map(|i| i.cancelled.load(Ordering::SeqCst))
+            .unwrap_or(false)
+    })
+}
+
+/// Dispatches invocations onto a fixed-size worker pool so that a burst of
+/// requests from the host cannot spawn unbounded concurrent work, and caps
+/// how many invocations may wait for a free worker so the same burst can't
+/// buffer an unbounded number of `InvocationRequest` payloads in memory
+/// either. `queued` and `running` are written to the worker's log (at debug
+/// level) whenever the host sends a `WorkerStatusRequest`, so back-pressure
+/// is visible; `WorkerStatusResponse` itself does not carry them.
+struct InvocationPool {
+    pool: ThreadPool,
+    max_queued: usize,
+    queued: AtomicUsize,
+    running: AtomicUsize,
+}
+
+impl InvocationPool {
+    fn new(max_concurrent_invocations: usize, max_queued_invocations: usize) -> InvocationPool {
+        InvocationPool {
+            pool: ThreadPool::new(max_concurrent_invocations),
+            max_queued: max_queued_invocations,
+            queued: AtomicUsize::new(0),
+            running: AtomicUsize::new(0),
+        }
+    }
+
+    /// Queues `job` to run on the pool and returns `true`, unless
+    /// `max_queued_invocations` invocations are already waiting for a free
+    /// worker, in which case it returns `false` without queuing `job` and
+    /// the caller is responsible for rejecting the invocation.
+    fn try_execute(self: &Arc<Self>, job: impl FnOnce() + Send + 'static) -> bool {
+        if self.queued.fetch_add(1, Ordering::SeqCst) >= self.max_queued {
+            self.queued.fetch_sub(1, Ordering::SeqCst);
+            return false;
+        }
+
+        let pool = self.clone();
+        self.pool.execute(move || {
+            pool.queued.fetch_sub(1, Ordering::SeqCst);
+            pool.running.fetch_add(1, Ordering::SeqCst);
+
+            job();
+
+            pool.running.fetch_sub(1, Ordering::SeqCst);
+        });
+        true
+    }
+
+    /// Returns the current `(queued, running)` invocation counts.
+    fn counts(&self) -> (usize, usize) {
+        (
+            self.queued.load(Ordering::SeqCst),
+            self.running.load(Ordering::SeqCst),
+        )
+    }
+}
+
 pub struct Run<'a> {
     pub host: &'a str,
     pub port: u16,
     pub worker_id: &'a str,
+    pub max_concurrent_invocations: usize,
+    pub max_queued_invocations: usize,
+    pub shutdown_drain_timeout: Duration,
 }
 
 impl<'a> Run<'a> {
@@ -70,10 +212,51 @@ impl<'a> Run<'a> {
                     .value_name("MAXIMUM")
                     .help("The maximum message length to use for gRPC messages."),
             )
+            .arg(
+                Arg::with_name("max_concurrent_invocations")
+                    .long("maxConcurrentInvocations")
+                    .value_name("COUNT")
+                    .help(
+                        "The maximum number of invocations to run concurrently. \
+                         Defaults to the available parallelism.",
+                    ),
+            )
+            .arg(
+                Arg::with_name("max_queued_invocations")
+                    .long("maxQueuedInvocations")
+                    .value_name("COUNT")
+                    .help(
+                        "The maximum number of invocations allowed to wait for a free worker \
+                         before new ones are rejected. Defaults to 4 times \
+                         --maxConcurrentInvocations.",
+                    ),
+            )
+            .arg(
+                Arg::with_name("shutdown_drain_timeout")
+                    .long("shutdownDrainTimeoutSeconds")
+                    .value_name("SECONDS")
+                    .help(
+                        "The number of seconds to wait for in-flight invocations to drain \
+                         during a graceful shutdown before exiting anyway.",
+                    ),
+            )
     }
 
     pub fn execute(&self, mut registry: Registry<'static>) -> Result<(), String> {
-        ctrlc::set_handler(|| {}).expect("failed setting SIGINT handler");
+        let drain_timeout = self.shutdown_drain_timeout;
+
+        ctrlc::set_handler(move || {
+            println!("Shutdown requested; draining in-flight invocations.");
+            SHUTTING_DOWN.store(true, Ordering::SeqCst);
+            Run::drain_invocations(drain_timeout);
+            std::process::exit(0);
+        })
+        .map_err(|e| format!("failed to set SIGINT handler: {}", e))?;
+
+        let pool = Arc::new(InvocationPool::new(
+            self.max_concurrent_invocations,
+            self.max_queued_invocations,
+        ));
 
         println!(
             "Connecting to Azure Functions Host at {}:{}",
@@ -85,60 +268,118 @@ impl<'a> Run<'a> {
                 .connect(&format!("{}:{}", self.host, self.port)),
         );
 
-        let (rpc_sender, rpc_receiver) = client.event_stream().unwrap();
-
-        let run = rpc_sender
-            .send((
-                StreamingMessage {
-                    content: Some(Content::StartStream(StartStream {
-                        worker_id: self.worker_id.to_owned(),
-                    })),
-                    ..Default::default()
-                },
-                WriteFlags::default(),
-            ))
-            .map_err(|e| panic!("failed to send start stream message: {}", e))
-            .and_then(|mut rpc_sender| {
-                rpc_receiver
-                    .into_future()
-                    .map_err(|(e, _)| panic!("failed to read worker init request: {}", e))
-                    .and_then(move |(res, rpc_receiver)| {
-                        let (sender, mut receiver) = unbounded::<StreamingMessage>();
-
-                        thread::spawn(move || loop {
-                            match receiver.into_future().wait() {
-                                Ok((Some(msg), r)) => {
-                                    receiver = r;
-                                    rpc_sender = rpc_sender
-                                        .send((msg, WriteFlags::default()))
-                                        .wait()
-                                        .expect("failed to send message to host");
+        let (rpc_sender, rpc_receiver) = client
+            .event_stream()
+            .map_err(|e| format!("failed to open an event stream with the host: {}", e))?;
+
+        // Captures an unrecoverable initialization failure so it can be
+        // surfaced to our caller once the event loop exits. Everything past
+        // the initial handshake is handled as a logged, recoverable error
+        // instead of panicking the whole worker process.
+        let init_error: Arc<Mutex<Option<String>>> = Arc::new(Mutex::new(None));
+
+        let run = {
+            let init_error = init_error.clone();
+            rpc_sender
+                .send((
+                    StreamingMessage {
+                        content: Some(Content::StartStream(StartStream {
+                            worker_id: self.worker_id.to_owned(),
+                        })),
+                        ..Default::default()
+                    },
+                    WriteFlags::default(),
+                ))
+                .then(move |res| match res {
+                    Ok(rpc_sender) => Ok(rpc_sender),
+                    Err(e) => {
+                        *init_error.lock().unwrap() =
+                            Some(format!("failed to send start stream message: {}", e));
+                        Err(())
+                    }
+                })
+                .and_then(move |mut rpc_sender| {
+                    let init_error = init_error.clone();
+                    rpc_receiver
+                        .into_future()
+                        .then(move |res| match res {
+                            Ok((Some(req), rpc_receiver)) => Ok((req, rpc_receiver)),
+                            Ok((None, _)) => {
+                                *init_error.lock().unwrap() = Some(
+                                    "host closed the connection before sending a worker init request"
+                                        .to_string(),
+                                );
+                                Err(())
+                            }
+                            Err((e, _)) => {
+                                *init_error.lock().unwrap() =
+                                    Some(format!("failed to read worker init request: {}", e));
+                                Err(())
+                            }
+                        })
+                        .and_then(move |(req, rpc_receiver)| {
+                            let (sender, mut receiver) = unbounded::<StreamingMessage>();
+
+                            thread::spawn(move || loop {
+                                match receiver.into_future().wait() {
+                                    Ok((Some(msg), r)) => {
+                                        receiver = r;
+                                        let sent =
+                                            rpc_sender.send((msg, WriteFlags::default())).wait();
+                                        PENDING_MESSAGES.fetch_sub(1, Ordering::SeqCst);
+                                        match sent {
+                                            Ok(s) => rpc_sender = s,
+                                            Err(e) => {
+                                                error!("failed to send message to host: {}", e);
+                                                break;
+                                            }
+                                        }
+                                    }
+                                    Ok((None, _)) => break,
+                                    Err(_) => {
+                                        error!("failed to receive a message queued for the host");
+                                        break;
+                                    }
                                 }
-                                Ok((None, _)) => break,
-                                Err(_e) => panic!("failed to receive message to send"),
+                            });
+
+                            if let Err(e) = Run::handle_worker_init_request(sender.clone(), req) {
+                                *init_error.lock().unwrap() = Some(e);
+                                return Err(());
                             }
-                        });
-
-                        Run::handle_worker_init_request(
-                            sender.clone(),
-                            res.expect("expected a worker init request"),
-                        );
-
-                        rpc_receiver
-                            .for_each(move |req| {
-                                Run::handle_request(&mut registry, sender.clone(), req);
-                                Ok(())
-                            })
-                            .map_err(|e| panic!("failed to read request: {}", e))
-                    })
-            });
+
+                            rpc_receiver
+                                .for_each(move |req| {
+                                    Run::handle_request(&mut registry, &pool, sender.clone(), req);
+                                    Ok(())
+                                })
+                                .then(move |res| {
+                                    if let Err(e) = res {
+                                        error!("lost connection to the host: {}", e);
+                                    }
+
+                                    // The host ended (or broke) the event stream; stop
+                                    // accepting new invocations and drain in-flight ones
+                                    // before this future completes and the worker exits,
+                                    // the same as an operator-initiated SIGINT would.
+                                    SHUTTING_DOWN.store(true, Ordering::SeqCst);
+                                    Run::drain_invocations(drain_timeout);
+
+                                    Ok(())
+                                })
+                        })
+                })
+        };
 
         tokio::run(run);
 
-        Ok(())
+        match init_error.lock().unwrap().take() {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
     }
 
-    fn handle_worker_init_request(sender: Sender, req: StreamingMessage) {
+    fn handle_worker_init_request(sender: Sender, req: StreamingMessage) -> Result<(), String> {
         match req.content {
             Some(Content::WorkerInitRequest(req)) => {
                 println!(
@@ -146,19 +387,45 @@ impl<'a> Run<'a> {
                     req.host_version
                 );
 
-                // TODO: use the level requested by the Azure functions host
-                log::set_boxed_logger(Box::new(logger::Logger::new(
-                    log::Level::Info,
+                let level = req
+                    .capabilities
+                    .get("RpcWorkerLogLevel")
+                    .map(|v| logger::parse_level(v))
+                    .unwrap_or(log::Level::Info);
+
+                let category_levels = req
+                    .capabilities
+                    .get("RpcWorkerLogCategoryFilter")
+                    .map(|v| logger::parse_category_levels(v))
+                    .unwrap_or_default();
+
+                // The `log` crate's macros gate on `log::max_level()` before a
+                // record ever reaches `Logger::enabled`/`Logger::log`, so the
+                // global max has to be the *most verbose* of the default level
+                // and every per-category override, or a category asking for a
+                // more verbose level than the default would be silently
+                // discarded before `Logger::level_for` ever sees it.
+                let max_level = category_levels
+                    .values()
+                    .copied()
+                    .chain(std::iter::once(level))
+                    .max()
+                    .unwrap_or(level);
+
+                log::set_boxed_logger(Box::new(logger::Logger::with_category_levels(
+                    level,
+                    category_levels,
                     sender.clone(),
                 )))
                 .expect("failed to set the global logger instance");
 
                 set_hook(Box::new(Run::handle_panic));
 
-                log::set_max_level(log::LevelFilter::Trace);
+                log::set_max_level(max_level.to_level_filter());
 
-                sender
-                    .unbounded_send(StreamingMessage {
+                enqueue(
+                    &sender,
+                    StreamingMessage {
                         content: Some(Content::WorkerInitResponse(WorkerInitResponse {
                             worker_version: env!("CARGO_PKG_VERSION").to_owned(),
                             result: Some(StatusResult {
@@ -168,28 +435,36 @@ impl<'a> Run<'a> {
                             ..Default::default()
                         })),
                         ..Default::default()
-                    })
-                    .unwrap();
+                    },
+                )
+                .map_err(|e| format!("failed to send worker init response: {}", e))
             }
-            _ => panic!("expected a worker init request message from the host"),
-        };
+            _ => Err("expected a worker init request message from the host".to_string()),
+        }
     }
 
-    fn handle_request(registry: &mut Registry<'static>, sender: Sender, req: StreamingMessage) {
+    fn handle_request(
+        registry: &mut Registry<'static>,
+        pool: &Arc<InvocationPool>,
+        sender: Sender,
+        req: StreamingMessage,
+    ) {
         match req.content {
             Some(Content::FunctionLoadRequest(req)) => {
                 Run::handle_function_load_request(registry, sender, req)
             }
             Some(Content::InvocationRequest(req)) => {
-                Run::handle_invocation_request(registry, sender, req)
+                Run::handle_invocation_request(registry, pool, sender, req)
             }
             Some(Content::WorkerStatusRequest(req)) => {
-                Run::handle_worker_status_request(sender, req)
+                Run::handle_worker_status_request(pool, sender, req)
             }
             Some(Content::FileChangeEventRequest(_)) => {}
-            Some(Content::InvocationCancel(_)) => {}
-            Some(Content::FunctionEnvironmentReloadRequest(_)) => {}
-            _ => panic!("unexpected message from host: {:?}.", req),
+            Some(Content::InvocationCancel(req)) => Run::handle_invocation_cancel(sender, req),
+            Some(Content::FunctionEnvironmentReloadRequest(req)) => {
+                Run::handle_function_environment_reload_request(sender, req)
+            }
+            _ => error!("received an unexpected message from the host: {:?}", req),
         };
     }
 
@@ -215,59 +490,255 @@ impl<'a> Run<'a> {
             }
         };
 
-        sender
-            .unbounded_send(StreamingMessage {
+        if let Err(e) = enqueue(
+            &sender,
+            StreamingMessage {
                 content: Some(Content::FunctionLoadResponse(FunctionLoadResponse {
                     function_id: req.function_id,
                     result: Some(result),
                     ..Default::default()
                 })),
                 ..Default::default()
-            })
-            .expect("failed to send function load response");
+            },
+        ) {
+            error!("failed to send function load response: {}", e);
+        }
+    }
+
+    /// Blocks until `done` returns true or `timeout` elapses, whichever comes
+    /// first, polling on a short interval.
+    fn wait_until(timeout: Duration, mut done: impl FnMut() -> bool) {
+        let start = Instant::now();
+        while !done() && start.elapsed() < timeout {
+            thread::sleep(Duration::from_millis(50));
+        }
+    }
+
+    /// Blocks until all in-flight invocations have completed and all queued
+    /// messages (responses and buffered log lines) have been handed off to
+    /// the host, or `timeout` elapses, whichever comes first.
+    fn drain_invocations(timeout: Duration) {
+        Run::wait_until(timeout, || {
+            INVOCATIONS.lock().unwrap().is_empty() && PENDING_MESSAGES.load(Ordering::SeqCst) == 0
+        });
     }
 
     fn handle_invocation_request(
         registry: &Registry<'static>,
+        pool: &Arc<InvocationPool>,
         sender: Sender,
         req: InvocationRequest,
     ) {
+        if SHUTTING_DOWN.load(Ordering::SeqCst) {
+            if let Err(e) = enqueue(
+                &sender,
+                StreamingMessage {
+                    content: Some(Content::InvocationResponse(InvocationResponse {
+                        invocation_id: req.invocation_id,
+                        result: Some(StatusResult {
+                            status: Status::Failure as i32,
+                            result: "Worker is shutting down and is not accepting new invocations."
+                                .to_string(),
+                            ..Default::default()
+                        }),
+                        ..Default::default()
+                    })),
+                    ..Default::default()
+                },
+            ) {
+                error!("failed to send invocation response: {}", e);
+            }
+            return;
+        }
+
         if let Some(func) = registry.get(&req.function_id) {
-            tokio::spawn(lazy(move || {
-                Run::invoke_function(func, sender, req);
-                Ok(())
-            }));
+            let invocation = Arc::new(Invocation::new());
+            let invocation_id = req.invocation_id.clone();
+            INVOCATIONS
+                .lock()
+                .unwrap()
+                .insert(invocation_id.clone(), invocation.clone());
+
+            let accepted = {
+                let sender = sender.clone();
+                pool.try_execute(move || {
+                    Run::invoke_function(func, sender, req, invocation);
+                })
+            };
+
+            if !accepted {
+                INVOCATIONS.lock().unwrap().remove(&invocation_id);
+
+                if let Err(e) = enqueue(
+                    &sender,
+                    StreamingMessage {
+                        content: Some(Content::InvocationResponse(InvocationResponse {
+                            invocation_id,
+                            result: Some(StatusResult {
+                                status: Status::Failure as i32,
+                                result: "Worker is at capacity and is not accepting new \
+                                         invocations."
+                                    .to_string(),
+                                ..Default::default()
+                            }),
+                            ..Default::default()
+                        })),
+                        ..Default::default()
+                    },
+                ) {
+                    error!("failed to send invocation response: {}", e);
+                }
+            }
             return;
         }
 
-        let error = format!("Function with id '{}' does not exist.", req.function_id);
+        let message = format!("Function with id '{}' does not exist.", req.function_id);
 
-        sender
-            .unbounded_send(StreamingMessage {
+        if let Err(e) = enqueue(
+            &sender,
+            StreamingMessage {
                 content: Some(Content::InvocationResponse(InvocationResponse {
                     invocation_id: req.invocation_id,
                     result: Some(StatusResult {
                         status: Status::Failure as i32,
-                        result: error,
+                        result: message,
                         ..Default::default()
                     }),
                     ..Default::default()
                 })),
                 ..Default::default()
-            })
-            .expect("failed to send invocation response");
+            },
+        ) {
+            error!("failed to send invocation response: {}", e);
+        }
+    }
+
+    fn handle_invocation_cancel(sender: Sender, req: InvocationCancel) {
+        let invocation = match INVOCATIONS.lock().unwrap().get(&req.invocation_id) {
+            Some(invocation) => invocation.clone(),
+            None => return,
+        };
+
+        invocation.cancelled.store(true, Ordering::SeqCst);
+
+        if invocation
+            .responded
+            .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
+            .is_ok()
+        {
+            if let Err(e) = enqueue(
+                &sender,
+                StreamingMessage {
+                    content: Some(Content::InvocationResponse(InvocationResponse {
+                        invocation_id: req.invocation_id,
+                        result: Some(StatusResult {
+                            status: Status::Cancelled as i32,
+                            ..Default::default()
+                        }),
+                        ..Default::default()
+                    })),
+                    ..Default::default()
+                },
+            ) {
+                error!("failed to send invocation response: {}", e);
+            }
+        }
+    }
+
+    fn handle_function_environment_reload_request(
+        sender: Sender,
+        req: FunctionEnvironmentReloadRequest,
+    ) {
+        // This runs on the host event loop thread, which is the only thread
+        // that starts new invocations; blocking it here also prevents any
+        // new invocation from starting until the reload below has finished.
+        // Combined with waiting for currently in-flight invocations to
+        // finish, this keeps invocation threads from reading the environment
+        // while it's mutated, unless the wait below times out.
+        let mut invocations_remained = false;
+        Run::wait_until(ENVIRONMENT_RELOAD_DRAIN_TIMEOUT, || {
+            let drained = INVOCATIONS.lock().unwrap().is_empty();
+            invocations_remained = !drained;
+            drained
+        });
+        if invocations_remained {
+            error!(
+                "timed out waiting for in-flight invocations to complete before reloading \
+                 the environment; proceeding anyway"
+            );
+        }
+
+        for (key, _) in std::env::vars() {
+            if !req.environment_variables.contains_key(&key) {
+                std::env::remove_var(&key);
+            }
+        }
+
+        for (key, value) in &req.environment_variables {
+            std::env::set_var(key, value);
+        }
+
+        if !req.function_app_directory.is_empty() {
+            if let Err(e) = std::env::set_current_dir(&req.function_app_directory) {
+                error!(
+                    "failed to set current directory to '{}': {}",
+                    req.function_app_directory, e
+                );
+            }
+        }
+
+        if let Err(e) = enqueue(
+            &sender,
+            StreamingMessage {
+                content: Some(Content::FunctionEnvironmentReloadResponse(
+                    FunctionEnvironmentReloadResponse {
+                        result: Some(StatusResult {
+                            status: Status::Success as i32,
+                            ..Default::default()
+                        }),
+                        ..Default::default()
+                    },
+                )),
+                ..Default::default()
+            },
+        ) {
+            error!("failed to send function environment reload response: {}", e);
+        }
     }
 
-    fn handle_worker_status_request(sender: Sender, _: WorkerStatusRequest) {
-        sender
-            .unbounded_send(StreamingMessage {
+    fn handle_worker_status_request(
+        pool: &Arc<InvocationPool>,
+        sender: Sender,
+        _: WorkerStatusRequest,
+    ) {
+        // `WorkerStatusResponse` itself carries no payload (it's a liveness
+        // ping from the host), so back-pressure is surfaced through the log
+        // instead of the response.
+        let (queued, running) = pool.counts();
+        debug!(
+            "worker status requested: {} invocation(s) queued, {} running",
+            queued, running
+        );
+
+        if let Err(e) = enqueue(
+            &sender,
+            StreamingMessage {
                 content: Some(Content::WorkerStatusResponse(WorkerStatusResponse {})),
                 ..Default::default()
-            })
-            .expect("failed to send worker status response");
+            },
+        ) {
+            error!("failed to send worker status response: {}", e);
+        }
     }
 
-    fn invoke_function(func: &'static Function, sender: Sender, req: InvocationRequest) {
+    fn invoke_function(
+        func: &'static Function,
+        sender: Sender,
+        req: InvocationRequest,
+        invocation: Arc<Invocation>,
+    ) {
+        let invocation_id = req.invocation_id.clone();
+
         // Set the function name in TLS
         FUNCTION_NAME.with(|n| {
             *n.borrow_mut() = &func.name;
@@ -278,6 +749,11 @@ impl<'a> Run<'a> {
             id.borrow_mut().replace_range(.., &req.invocation_id);
         });
 
+        // Make the cancellation token available to the running function via TLS
+        CANCELLATION_TOKEN.with(|token| {
+            token.borrow_mut().replace(invocation.clone());
+        });
+
         let response = match catch_unwind(AssertUnwindSafe(|| {
             (func
                 .invoker
@@ -306,12 +782,30 @@ impl<'a> Run<'a> {
             id.borrow_mut().clear();
         });
 
-        sender
-            .unbounded_send(StreamingMessage {
-                content: Some(Content::InvocationResponse(response)),
-                ..Default::default()
-            })
-            .expect("failed to send invocation response");
+        // Clear the cancellation token from TLS
+        CANCELLATION_TOKEN.with(|token| {
+            token.borrow_mut().take();
+        });
+
+        INVOCATIONS.lock().unwrap().remove(&invocation_id);
+
+        // A cancellation may have already responded to the host while this
+        // invocation was running; don't send a second response in that case.
+        if invocation
+            .responded
+            .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
+            .is_ok()
+        {
+            if let Err(e) = enqueue(
+                &sender,
+                StreamingMessage {
+                    content: Some(Content::InvocationResponse(response)),
+                    ..Default::default()
+                },
+            ) {
+                error!("failed to send invocation response: {}", e);
+            }
+        }
     }
 
     fn handle_panic(info: &PanicInfo) {
@@ -365,6 +859,29 @@ impl<'a> From<&'a ArgMatches<'a>> for Run<'a> {
             worker_id: args
                 .value_of("worker_id")
                 .expect("A worker id is required."),
+            max_concurrent_invocations: args
+                .value_of("max_concurrent_invocations")
+                .map(|count| count.parse::<usize>().expect("Invalid invocation count"))
+                .unwrap_or_else(num_cpus::get),
+            max_queued_invocations: args
+                .value_of("max_queued_invocations")
+                .map(|count| count.parse::<usize>().expect("Invalid queued invocation count"))
+                .unwrap_or_else(|| {
+                    let max_concurrent_invocations = args
+                        .value_of("max_concurrent_invocations")
+                        .map(|count| count.parse::<usize>().expect("Invalid invocation count"))
+                        .unwrap_or_else(num_cpus::get);
+                    max_concurrent_invocations * DEFAULT_MAX_QUEUED_INVOCATIONS_MULTIPLIER
+                }),
+            shutdown_drain_timeout: args
+                .value_of("shutdown_drain_timeout")
+                .map(|secs| {
+                    Duration::from_secs(
+                        secs.parse::<u64>()
+                            .expect("Invalid shutdown drain timeout"),
+                    )
+                })
+                .unwrap_or(DEFAULT_SHUTDOWN_DRAIN_TIMEOUT),
         }
     }
 }
@@ -0,0 +1,122 @@
+use crate::commands::run;
+use crate::rpc::{rpc_log, streaming_message::Content, RpcLog, StreamingMessage};
+use futures::sync::mpsc::UnboundedSender;
+use log::{Level, Log, Metadata, Record};
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+thread_local!(pub static INVOCATION_ID: RefCell<String> = RefCell::new(String::new()));
+
+type Sender = UnboundedSender<StreamingMessage>;
+
+/// Logs messages from the worker to the Azure Functions host.
+///
+/// Supports a default level plus per-category overrides (matched by the
+/// longest registered prefix of the log record's target) so that users can
+/// quiet framework logs while keeping their function logs at a more verbose
+/// level, without recompiling the worker.
+pub struct Logger {
+    level: Level,
+    category_levels: HashMap<String, Level>,
+    sender: Sender,
+}
+
+impl Logger {
+    pub fn with_category_levels(
+        level: Level,
+        category_levels: HashMap<String, Level>,
+        sender: Sender,
+    ) -> Logger {
+        Logger {
+            level,
+            category_levels,
+            sender,
+        }
+    }
+
+    fn level_for(&self, category: &str) -> Level {
+        self.category_levels
+            .iter()
+            .filter(|(prefix, _)| category.starts_with(prefix.as_str()))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|(_, level)| *level)
+            .unwrap_or(self.level)
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level_for(metadata.target())
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let invocation_id = INVOCATION_ID.with(|id| id.borrow().clone());
+
+        let _ = run::enqueue(
+            &self.sender,
+            StreamingMessage {
+                content: Some(Content::RpcLog(RpcLog {
+                    invocation_id,
+                    category: record.target().to_string(),
+                    message: record.args().to_string(),
+                    log_category: rpc_log::RpcLogCategory::User as i32,
+                    level: to_rpc_level(record.level()) as i32,
+                    ..Default::default()
+                })),
+                ..Default::default()
+            },
+        );
+    }
+
+    fn flush(&self) {}
+}
+
+/// Maps a Rust [`log::Level`] to the RPC log level understood by the host.
+fn to_rpc_level(level: Level) -> rpc_log::Level {
+    match level {
+        Level::Trace => rpc_log::Level::Trace,
+        Level::Debug => rpc_log::Level::Debug,
+        Level::Info => rpc_log::Level::Information,
+        Level::Warn => rpc_log::Level::Warning,
+        Level::Error => rpc_log::Level::Error,
+    }
+}
+
+/// Parses a host-provided level string (e.g. `"Trace"`, `"Debug"`,
+/// `"Information"`, `"Warning"`, `"Error"`) into a [`log::Level`].
+///
+/// Unrecognized values fall back to `Level::Info` rather than failing worker
+/// initialization over a logging preference.
+pub fn parse_level(value: &str) -> Level {
+    match value {
+        "Trace" => Level::Trace,
+        "Debug" => Level::Debug,
+        "Warning" => Level::Warn,
+        "Error" | "Critical" => Level::Error,
+        _ => Level::Info,
+    }
+}
+
+/// Parses the `RpcWorkerLogCategoryFilter` capability value into a map of
+/// logger-category prefix to minimum level.
+///
+/// The expected format is a semicolon-separated list of `prefix=level`
+/// pairs, e.g. `"azure_functions::codegen=Warning;my_function=Debug"`.
+pub fn parse_category_levels(value: &str) -> HashMap<String, Level> {
+    value
+        .split(';')
+        .filter_map(|entry| {
+            let mut parts = entry.splitn(2, '=');
+            let prefix = parts.next()?.trim();
+            let level = parts.next()?.trim();
+            if prefix.is_empty() || level.is_empty() {
+                return None;
+            }
+            Some((prefix.to_string(), parse_level(level)))
+        })
+        .collect()
+}